@@ -1,20 +1,395 @@
-use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use rand::Rng;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
 
 #[inline]
 fn usage() {
-    println!("Usage: data-shifter [dir] --shift file...");
-    println!("       data-shifter [dir] --recover file...");
-    println!("       data-shifter [dir] --force-shift file...");
-    println!("       data-shifter [dir] --force-recover file...");
+    println!(
+        "Usage: data-shifter [dir] --shift [--shred-source] [--shred-passes <n>] [--key <passphrase>] [--versioned] file..."
+    );
+    println!("       data-shifter [dir] --restore [--key <passphrase>] [--version <n>] file...");
+    println!(
+        "       data-shifter [dir] --force-shift [--shred-source] [--shred-passes <n>] [--key <passphrase>] [--versioned] file..."
+    );
+    println!(
+        "       data-shifter [dir] --force-restore [--key <passphrase>] [--version <n>] file..."
+    );
+    println!("       data-shifter [dir] --list shifted-file...");
 }
 
 const MAGIC_NUM: &[u8; 7] = b"SHIFTED";
 
+// Default number of overwrite passes `--shred-source` performs before removing a file
+// (all but the last pass write cryptographically random bytes, the last writes zeros),
+// overridable with `--shred-passes <n>`.
+const SHRED_PASSES: u32 = 3;
+
+// Before the header carried an explicit format-version byte, the byte right after
+// `MAGIC_NUM` was the algorithm byte itself. `restore()` still recognizes these two
+// values there so pre-existing `.shift` files keep restoring.
+const ALGO_LEGACY_ADD: u8 = 0;
+const ALGO_KEYED_STREAM: u8 = 1;
+
+// Format-version byte written right after `MAGIC_NUM`. Chosen clear of the legacy
+// algorithm byte values above so `restore()` can tell old and new headers apart.
+// Followed by a flags byte (`FLAG_*`) describing the rest of the header layout.
+const FORMAT_VERSION: u8 = 2;
+
+const FLAG_KEYED_STREAM: u8 = 0b0000_0001;
+const FLAG_HAS_TAG: u8 = 0b0000_0010;
+
+// The three header shapes `restore()` has ever had to understand, oldest first.
+enum HeaderFormat {
+    // The true pre-series baseline, from before `MAGIC_NUM` was ever followed by a
+    // version/algo byte at all: the byte already consumed off the stream to tell the
+    // header apart *is* the random XOR value, and no tag or version/timestamp fields
+    // follow. Unavoidably ambiguous with the two formats below when a baseline file's
+    // random byte happens to collide with one of their discriminator values.
+    Baseline { random: u8 },
+    // The in-flight `ALGO_*` byte this series shipped before the header was made
+    // explicitly self-describing. Always carries a tag, since chunk0-3 (which added
+    // tags) had already landed by the time these were the current format.
+    Legacy { is_keyed: bool },
+    // The current explicit `FORMAT_VERSION` + flags header.
+    Versioned { is_keyed: bool, has_tag: bool },
+}
+
+// Tells apart the three header shapes above so callers can parse the rest of the
+// header uniformly.
+fn read_format_flags<R: Read>(reader: &mut R) -> io::Result<HeaderFormat> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    match byte[0] {
+        FORMAT_VERSION => {
+            let mut flags = [0u8; 1];
+            reader.read_exact(&mut flags)?;
+            Ok(HeaderFormat::Versioned {
+                is_keyed: flags[0] & FLAG_KEYED_STREAM != 0,
+                has_tag: flags[0] & FLAG_HAS_TAG != 0,
+            })
+        }
+        ALGO_LEGACY_ADD => Ok(HeaderFormat::Legacy { is_keyed: false }),
+        ALGO_KEYED_STREAM => Ok(HeaderFormat::Legacy { is_keyed: true }),
+        random => Ok(HeaderFormat::Baseline { random }),
+    }
+}
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+// Derives a 32-byte key from the passphrase and per-file salt via PBKDF2-HMAC-SHA256.
+fn derive_key(passphrase: &str, salt: &[u8; SALT_LEN]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+// Folds the derived key and the per-file nonce into the seed for the ChaCha20
+// keystream generator, so the same (passphrase, salt, nonce) always reproduces
+// the same keystream on both `shift()` and `restore()`.
+fn keystream_rng(key: &[u8; 32], nonce: &[u8; NONCE_LEN]) -> ChaCha20Rng {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(nonce);
+    let seed: [u8; 32] = hasher.finalize().into();
+    ChaCha20Rng::from_seed(seed)
+}
+
+const TAG_LEN: usize = 32;
+
+// Authenticates the plaintext so `restore()` can detect a wrong `--key` or a corrupted
+// file: HMAC-SHA256 keyed by the derived key in keyed mode, plain SHA-256 otherwise.
+enum Tag {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl Tag {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Tag::Plain(hasher) => hasher.update(data),
+            Tag::Keyed(mac) => mac.update(data),
+        }
+    }
+
+    fn finalize(self) -> [u8; TAG_LEN] {
+        match self {
+            Tag::Plain(hasher) => hasher.finalize().into(),
+            Tag::Keyed(mac) => mac.finalize().into_bytes().into(),
+        }
+    }
+}
+
+// Upper bound on a stored original-path length. Generous enough for any real relative
+// path, but small enough that a corrupt or malicious varint can't drive a multi-gigabyte
+// (or multi-exabyte) allocation before `restore()` gets a chance to reject the file.
+const MAX_NAME_LEN: u64 = 4096;
+
+// LEB128 varint, used for the original-path length so nested relative paths aren't
+// capped at 255 bytes like a plain `u8` would cap them.
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        writer.write_all(&[byte])?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        if shift >= 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+// Recursively collects every regular file under `root`.
+fn collect_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Err(_) => continue,
+            Ok(entries) => entries,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                stack.push(path);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+// Renders a path relative to a walked root as a `/`-separated string, so shifted
+// files move cleanly between OSes regardless of the native path separator.
+fn normalize_relative_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn now_unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Finds every `--versioned` shift of `output_base`, named `<output_base>.<n>`, sorted
+// oldest to newest.
+fn list_versions(output_base: &Path) -> Vec<(u32, PathBuf)> {
+    let parent = match output_base.parent() {
+        Some(parent) => parent,
+        None => return Vec::new(),
+    };
+    let base_name = match output_base.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Vec::new(),
+    };
+    let prefix = format!("{}.", base_name);
+    let mut versions = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(parent) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(suffix) = file_name.strip_prefix(&prefix) {
+                if let Ok(n) = suffix.parse::<u32>() {
+                    versions.push((n, entry.path()));
+                }
+            }
+        }
+    }
+    versions.sort_by_key(|(n, _)| *n);
+    versions
+}
+
+// Reads just enough of a shift header to recover the version and timestamp fields,
+// skipping over the algorithm-specific key material in between.
+fn read_version_header(path: &Path) -> Option<(u32, u64)> {
+    let file = File::open(path).ok()?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 7];
+    reader.read_exact(&mut magic).ok()?;
+    if &magic != MAGIC_NUM {
+        return None;
+    }
+    let is_keyed = match read_format_flags(&mut reader).ok()? {
+        // The baseline format predates both versioning and tags; it has no version or
+        // timestamp fields to read, so there's nothing to report here.
+        HeaderFormat::Baseline { .. } => return None,
+        HeaderFormat::Legacy { is_keyed } => is_keyed,
+        HeaderFormat::Versioned { is_keyed, .. } => is_keyed,
+    };
+    if is_keyed {
+        let mut salt_and_nonce = [0u8; SALT_LEN + NONCE_LEN];
+        reader.read_exact(&mut salt_and_nonce).ok()?;
+    } else {
+        let mut random_byte = [0u8; 1];
+        reader.read_exact(&mut random_byte).ok()?;
+    }
+    let mut version_bytes = [0u8; 4];
+    reader.read_exact(&mut version_bytes).ok()?;
+    let mut timestamp_bytes = [0u8; 8];
+    reader.read_exact(&mut timestamp_bytes).ok()?;
+    Some((
+        u32::from_le_bytes(version_bytes),
+        u64::from_le_bytes(timestamp_bytes),
+    ))
+}
+
+// Everything `restore()` needs out of a header, however it was laid out on disk.
+struct ParsedHeader {
+    random: u8,
+    stream: Option<ChaCha20Rng>,
+    tag: Option<Tag>,
+    original_name: String,
+    stored_tag: Option<[u8; TAG_LEN]>,
+}
+
+// Parses everything from right after `MAGIC_NUM` onward. The discriminator byte is
+// ambiguous: a baseline file's random XOR byte (drawn from `1..=255`) can collide with
+// the `ALGO_*`/`FORMAT_VERSION` values the newer layouts expect right there. So if the
+// newer-layout interpretation fails downstream (missing `--key`, a truncated follow-on
+// field, an oversized name, ...), rewind to right after `MAGIC_NUM` and retry as a
+// baseline header before giving up on the file entirely.
+fn parse_header<R: Read + Seek>(reader: &mut R, key: &Option<String>) -> io::Result<ParsedHeader> {
+    let header_start = reader.stream_position()?;
+    match read_format_flags(reader)? {
+        HeaderFormat::Baseline { random } => parse_baseline_header(reader, random),
+        format => match parse_versioned_or_legacy_header(reader, format, key) {
+            Ok(parsed) => Ok(parsed),
+            Err(_) => {
+                reader.seek(SeekFrom::Start(header_start))?;
+                let mut discriminator = [0u8; 1];
+                reader.read_exact(&mut discriminator)?;
+                parse_baseline_header(reader, discriminator[0])
+            }
+        },
+    }
+}
+
+// Baseline files predate both the version/timestamp and the tag: there's nothing to
+// read between the header and the name on that path, and the name length is a single
+// byte rather than a varint.
+fn parse_baseline_header<R: Read>(reader: &mut R, random: u8) -> io::Result<ParsedHeader> {
+    let mut len_byte = [0u8; 1];
+    reader.read_exact(&mut len_byte)?;
+    if len_byte[0] == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty name"));
+    }
+    let mut original_name = vec![0u8; len_byte[0] as usize];
+    reader.read_exact(&mut original_name)?;
+    Ok(ParsedHeader {
+        random,
+        stream: None,
+        tag: None,
+        original_name: String::from_utf8_lossy(&original_name).to_string(),
+        stored_tag: None,
+    })
+}
+
+fn parse_versioned_or_legacy_header<R: Read>(
+    reader: &mut R,
+    format: HeaderFormat,
+    key: &Option<String>,
+) -> io::Result<ParsedHeader> {
+    let (is_keyed, has_tag) = match format {
+        HeaderFormat::Legacy { is_keyed } => (is_keyed, true),
+        HeaderFormat::Versioned { is_keyed, has_tag } => (is_keyed, has_tag),
+        HeaderFormat::Baseline { .. } => unreachable!(),
+    };
+    let mut random = 0u8;
+    let mut stream = None;
+    let tag = if is_keyed {
+        let passphrase = key
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing --key"))?;
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        reader.read_exact(&mut salt)?;
+        reader.read_exact(&mut nonce)?;
+        let derived = derive_key(passphrase, &salt);
+        stream = Some(keystream_rng(&derived, &nonce));
+        has_tag.then(|| Tag::Keyed(Hmac::<Sha256>::new_from_slice(&derived).unwrap()))
+    } else {
+        let mut random_byte = [0u8; 1];
+        reader.read_exact(&mut random_byte)?;
+        random = random_byte[0];
+        has_tag.then(|| Tag::Plain(Sha256::new()))
+    };
+    let mut version_and_timestamp = [0u8; 4 + 8];
+    reader.read_exact(&mut version_and_timestamp)?;
+    let original_name_len = read_varint(reader)?;
+    if original_name_len == 0 || original_name_len > MAX_NAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad name length"));
+    }
+    let mut original_name = vec![0u8; original_name_len as usize];
+    reader.read_exact(&mut original_name)?;
+    let original_name = String::from_utf8_lossy(&original_name).to_string();
+    let stored_tag = if has_tag {
+        let mut stored_tag = [0u8; TAG_LEN];
+        reader.read_exact(&mut stored_tag)?;
+        Some(stored_tag)
+    } else {
+        None
+    };
+    Ok(ParsedHeader {
+        random,
+        stream,
+        tag,
+        original_name,
+        stored_tag,
+    })
+}
+
+// Takes the same kind of argument as `--restore --version`: the shifted base path
+// itself (e.g. `out/source.txt.shift`), not the original source name.
+fn list<I: Iterator<Item = String>>(_dir: PathBuf, mut args: I) {
+    while let Some(p) = args.next() {
+        let output_base = PathBuf::from(&p);
+        let versions = list_versions(&output_base);
+        if versions.is_empty() {
+            eprintln!("no versions found for '{}'", p);
+            continue;
+        }
+        for (version, path) in versions {
+            match read_version_header(&path) {
+                None => eprintln!("ignore invalid file '{}'", path.to_string_lossy()),
+                Some((_, timestamp)) => {
+                    println!("{}\tversion {}\ttimestamp {}", p, version, timestamp);
+                }
+            }
+        }
+    }
+}
+
 fn main() {
     let mut args = std::env::args().skip(1);
     let dir;
@@ -62,6 +437,9 @@ fn main() {
         "--force-restore" => {
             restore(dir, args, true);
         }
+        "--list" => {
+            list(dir, args);
+        }
         _ => {
             usage();
             return;
@@ -69,84 +447,403 @@ fn main() {
     }
 }
 
-fn shift<I: Iterator<Item = String>>(dir: PathBuf, mut args: I, force: bool) {
+// The flags that tune how a file gets shifted, bundled up so `shift_one` takes one
+// options argument instead of growing a new positional parameter with every flag.
+struct ShiftOptions {
+    force: bool,
+    shred_source: bool,
+    shred_passes: u32,
+    versioned: bool,
+    key: Option<String>,
+}
+
+fn shift<I: Iterator<Item = String>>(dir: PathBuf, args: I, force: bool) {
+    let mut args = args.peekable();
+    let mut shred_source = false;
+    let mut shred_passes = SHRED_PASSES;
+    let mut versioned = false;
+    let mut key = None;
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--shred-source") => {
+                args.next();
+                shred_source = true;
+            }
+            Some("--shred-passes") => {
+                args.next();
+                shred_passes = match args.next() {
+                    None => {
+                        eprintln!("--shred-passes requires a number argument");
+                        return;
+                    }
+                    Some(n) => match n.parse::<u32>() {
+                        Err(_) | Ok(0) => {
+                            eprintln!("invalid --shred-passes value '{}'", n);
+                            return;
+                        }
+                        Ok(n) => n,
+                    },
+                };
+            }
+            Some("--versioned") => {
+                args.next();
+                versioned = true;
+            }
+            Some("--key") => {
+                args.next();
+                key = match args.next() {
+                    None => {
+                        eprintln!("--key requires a passphrase argument");
+                        return;
+                    }
+                    Some(passphrase) => Some(passphrase),
+                };
+            }
+            _ => break,
+        }
+    }
+
+    let options = ShiftOptions {
+        force,
+        shred_source,
+        shred_passes,
+        versioned,
+        key,
+    };
+
     let mut rng = rand::thread_rng();
     let mut buffer = vec![0u8; 4096];
+    let mut keystream_buffer = vec![0u8; 4096];
     while let Some(p) = args.next() {
-        let file = match File::open(&p) {
-            Err(_) => {
+        let source_path = PathBuf::from(&p);
+        if source_path.is_dir() {
+            for file_path in collect_files(&source_path) {
+                let relative_name =
+                    normalize_relative_path(file_path.strip_prefix(&source_path).unwrap());
+                shift_one(
+                    &dir,
+                    &file_path,
+                    &relative_name,
+                    &options,
+                    &mut rng,
+                    &mut buffer,
+                    &mut keystream_buffer,
+                );
+            }
+            continue;
+        }
+        let name = match source_path.file_name() {
+            None => {
                 eprintln!("ignore invalid file '{}'", p);
                 continue;
             }
-            Ok(f) => f,
+            Some(name) => name.to_string_lossy().into_owned(),
         };
-        let path = PathBuf::from(&p);
-        let name = path.file_name().unwrap();
-        let output_path = dir.join(format!("{}.shift", name.to_string_lossy()));
-        let output_file = if force {
-            match OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(&output_path)
-            {
-                Err(_) => {
-                    eprintln!(
-                        "ignore file '{}'; failed to open file '{}' to write",
-                        p,
-                        output_path.to_string_lossy()
-                    );
-                    continue;
-                }
-                Ok(f) => f,
+        shift_one(
+            &dir,
+            &source_path,
+            &name,
+            &options,
+            &mut rng,
+            &mut buffer,
+            &mut keystream_buffer,
+        );
+    }
+}
+
+// Shifts a single source file, storing `relative_name` (already `/`-normalized) as its
+// original path so `restore()` can recreate it, possibly nested, under `dir`.
+fn shift_one(
+    dir: &Path,
+    source_path: &Path,
+    relative_name: &str,
+    options: &ShiftOptions,
+    rng: &mut impl Rng,
+    buffer: &mut [u8],
+    keystream_buffer: &mut [u8],
+) {
+    let force = options.force;
+    let shred_source = options.shred_source;
+    let shred_passes = options.shred_passes;
+    let versioned = options.versioned;
+    let key = &options.key;
+    let file = match File::open(source_path) {
+        Err(_) => {
+            eprintln!("ignore invalid file '{}'", source_path.to_string_lossy());
+            return;
+        }
+        Ok(f) => f,
+    };
+    let output_base = dir.join(format!("{}.shift", relative_name));
+    if let Some(parent) = output_base.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    let (output_path, version) = if versioned {
+        let next_version = list_versions(&output_base).last().map_or(1, |(n, _)| n + 1);
+        let output_path =
+            PathBuf::from(format!("{}.{}", output_base.to_string_lossy(), next_version));
+        (output_path, next_version)
+    } else {
+        (output_base, 1)
+    };
+    let output_file = if versioned {
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&output_path)
+        {
+            Err(_) => {
+                eprintln!(
+                    "ignore file '{}'; failed to create version file '{}'",
+                    source_path.to_string_lossy(),
+                    output_path.to_string_lossy()
+                );
+                return;
             }
-        } else {
-            match OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(&output_path)
-            {
-                Err(_) => {
-                    eprintln!(
-                        "ignore file '{}'; failed to create file '{}' to write",
-                        p,
-                        output_path.to_string_lossy()
-                    );
-                    continue;
+            Ok(f) => f,
+        }
+    } else if force {
+        match OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_path)
+        {
+            Err(_) => {
+                eprintln!(
+                    "ignore file '{}'; failed to open file '{}' to write",
+                    source_path.to_string_lossy(),
+                    output_path.to_string_lossy()
+                );
+                return;
+            }
+            Ok(f) => f,
+        }
+    } else {
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&output_path)
+        {
+            Err(_) => {
+                eprintln!(
+                    "ignore file '{}'; failed to create file '{}' to write",
+                    source_path.to_string_lossy(),
+                    output_path.to_string_lossy()
+                );
+                return;
+            }
+            Ok(f) => f,
+        }
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut writer = BufWriter::new(output_file);
+
+    writer.write_all(MAGIC_NUM).unwrap();
+    writer.write_all(&[FORMAT_VERSION]).unwrap();
+    let flags = FLAG_HAS_TAG | if key.is_some() { FLAG_KEYED_STREAM } else { 0 };
+    writer.write_all(&[flags]).unwrap();
+    let mut random = 0u8;
+    let mut stream = None;
+    let mut tag = match key {
+        None => {
+            random = rng.gen_range(1..=u8::MAX);
+            writer.write_all(&random.to_le_bytes()).unwrap();
+            Tag::Plain(Sha256::new())
+        }
+        Some(passphrase) => {
+            let mut salt = [0u8; SALT_LEN];
+            rng.fill(&mut salt);
+            let mut nonce = [0u8; NONCE_LEN];
+            rng.fill(&mut nonce);
+            writer.write_all(&salt).unwrap();
+            writer.write_all(&nonce).unwrap();
+            let derived = derive_key(passphrase, &salt);
+            stream = Some(keystream_rng(&derived, &nonce));
+            Tag::Keyed(Hmac::<Sha256>::new_from_slice(&derived).unwrap())
+        }
+    };
+    writer.write_all(&version.to_le_bytes()).unwrap();
+    writer.write_all(&now_unix_secs().to_le_bytes()).unwrap();
+    let relative_name_bytes = relative_name.as_bytes();
+    write_varint(&mut writer, relative_name_bytes.len() as u64).unwrap();
+    writer.write_all(relative_name_bytes).unwrap();
+
+    // The tag can only be computed after the whole plaintext has been read, so
+    // reserve its slot now and seek back to fill it in once the body is written.
+    let tag_pos = writer.stream_position().unwrap();
+    writer.write_all(&[0u8; TAG_LEN]).unwrap();
+
+    loop {
+        let num = reader.read(buffer).unwrap();
+        if num == 0 {
+            break;
+        }
+        tag.update(&buffer[..num]);
+        match &mut stream {
+            None => {
+                buffer[..num]
+                    .iter_mut()
+                    .for_each(|byte| *byte = u8::wrapping_add(*byte, random));
+            }
+            Some(stream) => {
+                stream.fill_bytes(&mut keystream_buffer[..num]);
+                for i in 0..num {
+                    buffer[i] ^= keystream_buffer[i];
                 }
-                Ok(f) => f,
             }
-        };
+        }
+        writer.write_all(&buffer[..num]).unwrap();
+    }
 
-        let random = rng.gen_range(1..=u8::MAX);
-        let mut reader = BufReader::new(file);
-        let mut writer = BufWriter::new(output_file);
+    let digest = tag.finalize();
+    writer.seek(SeekFrom::Start(tag_pos)).unwrap();
+    writer.write_all(&digest).unwrap();
 
-        writer.write_all(MAGIC_NUM).unwrap();
-        writer.write_all(&random.to_le_bytes()).unwrap();
-        let original_name = name.to_string_lossy();
-        let original_name = original_name.as_bytes();
-        let original_name_len = u8::try_from(original_name.len()).unwrap();
-        writer.write_all(&original_name_len.to_le_bytes()).unwrap();
-        writer.write_all(original_name).unwrap();
+    writer.flush().unwrap();
+    writer.get_ref().sync_all().unwrap();
 
-        loop {
-            let num = reader.read(&mut buffer).unwrap();
-            if num == 0 {
-                break;
+    if shred_source {
+        shred_source_file(source_path, &output_path, force, shred_passes, rng);
+    }
+}
+
+// Overwrites `path` in place for `passes` passes (random bytes, then a final zero pass),
+// fsync'ing after each so the OS actually commits it, then truncates and removes it. Only
+// called once the shifted copy at `output_path` has been fully written and synced.
+fn shred_source_file(
+    path: &Path,
+    output_path: &Path,
+    force: bool,
+    passes: u32,
+    rng: &mut impl Rng,
+) {
+    if force {
+        if let (Ok(source_canon), Ok(output_canon)) =
+            (path.canonicalize(), output_path.canonicalize())
+        {
+            if source_canon == output_canon {
+                eprintln!(
+                    "skip shredding '{}'; it aliases its own shifted output",
+                    path.to_string_lossy()
+                );
+                return;
             }
-            buffer[..num]
-                .iter_mut()
-                .for_each(|byte| *byte = u8::wrapping_add(*byte, random));
-            writer.write_all(&buffer[..num]).unwrap();
         }
     }
+
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Err(_) => {
+            eprintln!("failed to reopen '{}' for shredding", path.to_string_lossy());
+            return;
+        }
+        Ok(f) => f,
+    };
+    let len = match file.metadata() {
+        Err(_) => {
+            eprintln!("failed to stat '{}' for shredding", path.to_string_lossy());
+            return;
+        }
+        Ok(m) => m.len(),
+    };
+
+    if len > 0 {
+        let mut buffer = vec![0u8; 4096];
+        for pass in 0..passes {
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let mut remaining = len;
+            while remaining > 0 {
+                let chunk = remaining.min(buffer.len() as u64) as usize;
+                if pass + 1 == passes {
+                    buffer[..chunk].iter_mut().for_each(|byte| *byte = 0);
+                } else {
+                    rng.fill(&mut buffer[..chunk]);
+                }
+                file.write_all(&buffer[..chunk]).unwrap();
+                remaining -= chunk as u64;
+            }
+            file.flush().unwrap();
+            file.sync_all().unwrap();
+        }
+    }
+
+    file.set_len(0).unwrap();
+    drop(file);
+    if let Err(_) = std::fs::remove_file(path) {
+        eprintln!("failed to remove shredded file '{}'", path.to_string_lossy());
+    }
 }
 
-fn restore<I: Iterator<Item = String>>(dir: PathBuf, mut args: I, force: bool) {
+fn restore<I: Iterator<Item = String>>(dir: PathBuf, args: I, force: bool) {
+    let mut args = args.peekable();
+    let mut key = None;
+    let mut version_filter = None;
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--key") => {
+                args.next();
+                key = match args.next() {
+                    None => {
+                        eprintln!("--key requires a passphrase argument");
+                        return;
+                    }
+                    Some(passphrase) => Some(passphrase),
+                };
+            }
+            Some("--version") => {
+                args.next();
+                version_filter = match args.next() {
+                    None => {
+                        eprintln!("--version requires a number argument");
+                        return;
+                    }
+                    Some(v) => match v.parse::<u32>() {
+                        Err(_) => {
+                            eprintln!("invalid --version value '{}'", v);
+                            return;
+                        }
+                        Ok(n) => Some(n),
+                    },
+                };
+            }
+            _ => break,
+        }
+    }
+
     let mut buffer = vec![0u8; 4096];
+    let mut keystream_buffer = vec![0u8; 4096];
     while let Some(p) = args.next() {
-        let file = match File::open(&p) {
+        // `p` names a concrete file unless `--version` was passed (an explicit request
+        // to resolve a `--versioned` sibling) or `p` itself doesn't exist, in which case
+        // look for `<p>.<n>` siblings and pick the requested version or the latest. This
+        // way a plain `--restore` of a literal, non-versioned path is never silently
+        // redirected to an unrelated version just because one happens to exist alongside it.
+        let resolved_path = if version_filter.is_none() && Path::new(&p).is_file() {
+            PathBuf::from(&p)
+        } else {
+            let versions = list_versions(Path::new(&p));
+            if versions.is_empty() {
+                if version_filter.is_some() {
+                    eprintln!("no versions found for '{}'", p);
+                    continue;
+                }
+                PathBuf::from(&p)
+            } else {
+                let chosen = match version_filter {
+                    Some(n) => versions.into_iter().find(|(v, _)| *v == n),
+                    None => versions.into_iter().last(),
+                };
+                match chosen {
+                    None => {
+                        eprintln!("no such version for '{}'", p);
+                        continue;
+                    }
+                    Some((_, path)) => path,
+                }
+            }
+        };
+        let file = match File::open(&resolved_path) {
             Err(_) => {
                 eprintln!("ignore invalid file '{}'", p);
                 continue;
@@ -154,33 +851,35 @@ fn restore<I: Iterator<Item = String>>(dir: PathBuf, mut args: I, force: bool) {
             Ok(f) => f,
         };
         let mut reader = BufReader::new(file);
-        let mut header = [0u8; 8];
-        if let Err(_) = reader.read_exact(&mut header) {
-            eprintln!("ignore invalid file '{}'", p);
-            continue;
-        }
-        if &header[..7] != MAGIC_NUM {
-            eprintln!("ignore invalid file '{}'", p);
-            continue;
-        }
-        let random = header[7];
-        let mut original_name_len = 0u8.to_le_bytes();
-        if let Err(_) = reader.read_exact(&mut original_name_len) {
+        let mut magic = [0u8; 7];
+        if let Err(_) = reader.read_exact(&mut magic) {
             eprintln!("ignore invalid file '{}'", p);
             continue;
         }
-        let original_name_len = u8::from_le_bytes(original_name_len);
-        if original_name_len == 0 {
+        if magic != *MAGIC_NUM {
             eprintln!("ignore invalid file '{}'", p);
             continue;
         }
-        let mut original_name = vec![0u8; original_name_len as usize];
-        if let Err(_) = reader.read_exact(&mut original_name) {
-            eprintln!("ignore invalid file '{}'", p);
-            continue;
+        let ParsedHeader {
+            random,
+            mut stream,
+            mut tag,
+            original_name,
+            stored_tag,
+        } = match parse_header(&mut reader, &key) {
+            Err(e) => {
+                eprintln!("ignore file '{}'; {}", p, e);
+                continue;
+            }
+            Ok(parsed) => parsed,
+        };
+        // The stored name is always `/`-separated; rebuild it with the native
+        // separator and recreate any intermediate directories it implies.
+        let relative_path: PathBuf = original_name.split('/').collect();
+        let output_path = dir.join(&relative_path);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
         }
-        let original_name = String::from_utf8_lossy(&original_name).to_string();
-        let output_path = dir.join(original_name);
         let output_file = if force {
             match OpenOptions::new()
                 .create(true)
@@ -222,10 +921,43 @@ fn restore<I: Iterator<Item = String>>(dir: PathBuf, mut args: I, force: bool) {
             if num == 0 {
                 break;
             }
-            buffer[..num]
-                .iter_mut()
-                .for_each(|byte| *byte = u8::wrapping_sub(*byte, random));
+            // A short read (e.g. the tail of the reader's buffer left after the header)
+            // would make `fill_bytes` below drop the unused bytes of a partly used
+            // keystream word, so top the chunk up to a full buffer, as `shift_one` reads
+            // it, and both sides consume the keystream in the same 4096-byte steps.
+            let mut num = num;
+            while num < buffer.len() {
+                let read = reader.read(&mut buffer[num..]).unwrap();
+                if read == 0 {
+                    break;
+                }
+                num += read;
+            }
+            match &mut stream {
+                None => {
+                    buffer[..num]
+                        .iter_mut()
+                        .for_each(|byte| *byte = u8::wrapping_sub(*byte, random));
+                }
+                Some(stream) => {
+                    stream.fill_bytes(&mut keystream_buffer[..num]);
+                    for i in 0..num {
+                        buffer[i] ^= keystream_buffer[i];
+                    }
+                }
+            }
+            if let Some(tag) = &mut tag {
+                tag.update(&buffer[..num]);
+            }
             writer.write_all(&buffer[..num]).unwrap();
         }
+
+        if let (Some(tag), Some(stored_tag)) = (tag, stored_tag) {
+            if tag.finalize() != stored_tag {
+                drop(writer);
+                let _ = std::fs::remove_file(&output_path);
+                eprintln!("integrity check failed for '{}'", p);
+            }
+        }
     }
 }